@@ -0,0 +1,24 @@
+use std::fmt::Debug;
+use std::path::PathBuf;
+
+/// Bound on the value type an in-memory/on-disk accounts index can store.
+pub trait IndexValue: 'static + Clone + Copy + Debug + Default + Send + Sync {}
+
+impl<T> IndexValue for T where T: 'static + Clone + Copy + Debug + Default + Send + Sync {}
+
+/// Tunables for [`crate::accounts_index_storage::AccountsIndexStorage`] and
+/// the [`crate::bucket_map_holder::BucketMapHolder`] it owns. All fields are
+/// optional; unset fields fall back to the storage's own defaults.
+#[derive(Debug, Clone, Default)]
+pub struct AccountsIndexConfig {
+    /// directories to use for the on-disk bucket map; `None` keeps the
+    /// index fully in memory
+    pub drives: Option<Vec<PathBuf>>,
+    /// number of flusher threads to start active; defaults to available
+    /// parallelism capped by `max_flush_threads`
+    pub flush_threads: Option<usize>,
+    /// hard ceiling on how many flusher threads the backlog-pressure
+    /// controller is ever allowed to grow the pool to; defaults to a small
+    /// fixed cap so a large bin count can't spawn one OS thread per bin
+    pub max_flush_threads: Option<usize>,
+}
@@ -2,10 +2,11 @@ use crate::accounts_index::{AccountsIndexConfig, IndexValue};
 use crate::bucket_map_holder::BucketMapHolder;
 use crate::in_mem_accounts_index::InMemAccountsIndex;
 use std::fmt::Debug;
-use std::time::Duration;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 use std::{
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
     thread::{Builder, JoinHandle},
@@ -23,6 +24,10 @@ pub struct AccountsIndexStorage<T: IndexValue> {
     // eventually the backing storage
     pub storage: Arc<BucketMapHolder<T>>,
     pub in_mem: Vec<Arc<InMemAccountsIndex<T>>>,
+
+    // shared state the pool controller uses to grow/shrink how many of the
+    // already-spawned flusher threads are currently doing work
+    flusher_pool: Arc<FlusherPool>,
 }
 
 impl<T: IndexValue> Debug for AccountsIndexStorage<T> {
@@ -33,12 +38,162 @@ impl<T: IndexValue> Debug for AccountsIndexStorage<T> {
 
 impl<T: IndexValue> Drop for AccountsIndexStorage<T> {
     fn drop(&mut self) {
-        self.exit.store(true, Ordering::Relaxed);
-        self.storage.wait_dirty_or_aged.notify_all();
-        if let Some(handles) = self.handles.take() {
-            handles
-                .into_iter()
-                .for_each(|handle| handle.join().unwrap());
+        // best-effort: bounded and panic-tolerant, so a stuck or already-
+        // panicked flusher thread can't hang or panic the dropping thread.
+        // Callers that need the `ShutdownReport` or a non-default deadline
+        // should call `shutdown`/`shutdown_with_timeout` explicitly first.
+        let _ = self.shutdown_with_timeout(DEFAULT_JOIN_TIMEOUT);
+    }
+}
+
+/// Default per-thread deadline `Drop` and [`AccountsIndexStorage::shutdown`]
+/// wait for a flusher/controller thread to finish before giving up on it.
+const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What happened to a single background thread during shutdown.
+#[derive(Debug)]
+enum JoinOutcome {
+    Joined,
+    Panicked(String),
+    TimedOut,
+}
+
+/// Summary of what happened when shutting down the background threads, so
+/// callers can tell whether everything was flushed before teardown.
+#[derive(Debug, Default, Clone)]
+pub struct ShutdownReport {
+    /// names of threads that exited cleanly
+    pub joined: Vec<String>,
+    /// names of threads that panicked, paired with the panic message
+    pub panicked: Vec<(String, String)>,
+    /// names of threads still running when their join deadline passed;
+    /// these were detached rather than blocking shutdown indefinitely, so
+    /// whatever bin they were mid-flushing on may not be persisted
+    pub timed_out: Vec<String>,
+}
+
+impl ShutdownReport {
+    fn is_clean(&self) -> bool {
+        self.panicked.is_empty() && self.timed_out.is_empty()
+    }
+}
+
+/// Returned by [`AccountsIndexStorage::shutdown`] when one or more
+/// background threads did not shut down cleanly. Carries the
+/// [`ShutdownReport`] describing exactly which threads panicked or timed out.
+#[derive(Debug, Clone)]
+pub struct ShutdownError(pub ShutdownReport);
+
+impl std::fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "accounts index shutdown was not clean: {} panicked, {} timed out",
+            self.0.panicked.len(),
+            self.0.timed_out.len()
+        )
+    }
+}
+
+impl std::error::Error for ShutdownError {}
+
+pub type ShutdownResult = Result<ShutdownReport, ShutdownError>;
+
+/// Joins `handle` on a dedicated reaper thread and waits at most `timeout`
+/// for it, so a flusher stuck mid-`flush()` can't hang the caller. If the
+/// deadline passes the reaper is left running and detached; we simply stop
+/// waiting on it rather than blocking indefinitely.
+fn join_with_timeout(handle: JoinHandle<()>, timeout: Duration) -> JoinOutcome {
+    let (tx, rx) = mpsc::channel();
+    let _ = Builder::new()
+        .name("solana-idx-reaper".to_string())
+        .spawn(move || {
+            let result = handle.join();
+            let _ = tx.send(result);
+        });
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(())) => JoinOutcome::Joined,
+        Ok(Err(panic)) => JoinOutcome::Panicked(panic_message(&*panic)),
+        Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+            JoinOutcome::TimedOut
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send + 'static)) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        (*s).to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+/// How often the controller re-evaluates whether the flusher pool should
+/// grow or shrink.
+const CONTROLLER_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Number of consecutive controller cycles the backlog has to stay above
+/// `BACKLOG_GROW_THRESHOLD` before we wake up another flusher thread.
+const BACKLOG_GROW_CYCLES: u32 = 3;
+
+/// Dirty-bin backlog (summed across all bins) above which the pool is
+/// considered under pressure.
+const BACKLOG_GROW_THRESHOLD: usize = 1000;
+
+/// Number of consecutive controller cycles the backlog has to stay at or
+/// below `BACKLOG_SHRINK_THRESHOLD` before we park a flusher thread back
+/// down, symmetric with the grow side.
+const BACKLOG_SHRINK_CYCLES: u32 = 3;
+
+/// Dirty-bin backlog at or below which the pool is considered to have
+/// spare capacity worth shrinking. Deliberately not zero: under a steady
+/// trickle of writes the backlog may never fully drain even though it's
+/// nowhere near `BACKLOG_GROW_THRESHOLD`, and requiring exactly zero would
+/// leave a pool that grew once during a burst grown forever.
+const BACKLOG_SHRINK_THRESHOLD: usize = BACKLOG_GROW_THRESHOLD / 10;
+
+/// Single-bin flush latency above which the pool is considered under
+/// pressure, alongside (not instead of) the backlog count: a pool that's
+/// only keeping the backlog down by running slow flushes still needs more
+/// hands.
+const FLUSH_LATENCY_GROW_THRESHOLD: Duration = Duration::from_millis(50);
+
+/// Default ceiling on how many flusher threads the pressure controller is
+/// ever allowed to grow the pool to, when `AccountsIndexConfig` doesn't
+/// specify one. Deliberately independent of bin count: the real accounts
+/// index has thousands of bins, and spawning one OS thread per bin would
+/// just trade the old rayon-thread-exhaustion deadlock for a native-thread
+/// one.
+const DEFAULT_MAX_FLUSH_THREADS: usize = 8;
+
+/// Fallback idle wait for a flusher thread when the holder has no bin
+/// scheduled (nothing dirty, nothing aging out). Matches the old fixed poll
+/// interval so a totally quiet index still gets serviced eventually.
+const MAX_IDLE_WAIT: Duration = Duration::from_millis(10_000);
+
+/// Minimum gap between flush passes on a single flusher thread, so a burst
+/// of bins going dirty at once is coalesced into one pass instead of
+/// waking the thread repeatedly.
+const MIN_CYCLE_GAP: Duration = Duration::from_millis(50);
+
+/// Shared state consulted by the flusher threads and mutated by the
+/// controller thread to grow or shrink the *active* flusher set. All
+/// flusher threads up to `max_threads` are spawned up front; threads whose
+/// id falls at or above `target_active` simply park (do no work, touch no
+/// bins) until the controller raises the target again.
+#[derive(Debug)]
+pub(crate) struct FlusherPool {
+    target_active: AtomicUsize,
+    max_threads: usize,
+}
+
+impl FlusherPool {
+    fn new(initial: usize, max_threads: usize) -> Self {
+        Self {
+            target_active: AtomicUsize::new(initial),
+            max_threads,
         }
     }
 }
@@ -48,41 +203,75 @@ impl<T: IndexValue> AccountsIndexStorage<T> {
         let storage = Arc::new(BucketMapHolder::new(bins, config));
 
         let in_mem = (0..bins)
-            .into_iter()
             .map(|bin| Arc::new(InMemAccountsIndex::new(&storage, bin)))
             .collect::<Vec<_>>();
 
-        const DEFAULT_THREADS: usize = 1; // soon, this will be a cpu calculation
+        // `max_flush_threads` bounds how far the controller is ever allowed
+        // to grow the pool; unlike `bins` (which can be in the thousands)
+        // this defaults to a small fixed cap.
+        let max_threads = config
+            .as_ref()
+            .and_then(|config| config.max_flush_threads)
+            .unwrap_or(DEFAULT_MAX_FLUSH_THREADS)
+            .max(1);
+        // Default to the available parallelism, capped by the number of
+        // bins (more flusher threads than bins just contend with each
+        // other) and by `max_threads`.
+        let default_threads = num_cpus::get().max(1).min(bins.max(1)).min(max_threads);
         let threads = config
             .as_ref()
             .and_then(|config| config.flush_threads)
-            .unwrap_or(DEFAULT_THREADS);
+            .unwrap_or(default_threads)
+            .clamp(1, max_threads);
 
         let exit = Arc::new(AtomicBool::default());
-        let handles = Some(
-            (0..threads)
-                .into_iter()
-                .map(|_| {
-                    let storage_ = Arc::clone(&storage);
-                    let exit_ = Arc::clone(&exit);
-                    let in_mem_ = in_mem.clone();
-
-                    // note that rayon use here causes us to exhaust # rayon threads and many tests running in parallel deadlock
-                    Builder::new()
-                        .name("solana-idx-flusher".to_string())
-                        .spawn(move || {
-                            Self::background(storage_, exit_, in_mem_);
-                        })
-                        .unwrap()
-                })
-                .collect(),
-        );
+        let flusher_pool = Arc::new(FlusherPool::new(threads, max_threads));
+
+        // Spawn the full `max_threads` pool up front; only `threads` of
+        // them start out active, the rest park immediately. Pre-spawning
+        // means growing the pool later is just flipping `target_active`,
+        // with no thread-spawn latency or extra bookkeeping for shutdown.
+        let mut handles = Vec::with_capacity(max_threads + 1);
+        for id in 0..max_threads {
+            let storage_ = Arc::clone(&storage);
+            let exit_ = Arc::clone(&exit);
+            let in_mem_ = in_mem.clone();
+            let flusher_pool_ = Arc::clone(&flusher_pool);
+
+            // note that rayon use here causes us to exhaust # rayon threads and many tests running in parallel deadlock
+            handles.push(
+                Builder::new()
+                    .name("solana-idx-flusher".to_string())
+                    .spawn(move || {
+                        Self::background(storage_, exit_, in_mem_, flusher_pool_, id);
+                    })
+                    .unwrap(),
+            );
+        }
+
+        // Controller thread: watches the per-cycle dirty-bin backlog and
+        // latency and grows the active flusher count when pressure
+        // persists, parking flushers back down once the backlog drains.
+        {
+            let storage_ = Arc::clone(&storage);
+            let exit_ = Arc::clone(&exit);
+            let flusher_pool_ = Arc::clone(&flusher_pool);
+            handles.push(
+                Builder::new()
+                    .name("solana-idx-flush-ctrl".to_string())
+                    .spawn(move || {
+                        Self::controller(storage_, exit_, flusher_pool_);
+                    })
+                    .unwrap(),
+            );
+        }
 
         Self {
             exit,
-            handles,
+            handles: Some(handles),
             storage,
             in_mem,
+            flusher_pool,
         }
     }
 
@@ -90,32 +279,290 @@ impl<T: IndexValue> AccountsIndexStorage<T> {
         &self.storage
     }
 
-    // intended to execute in a bg thread
-    pub fn background(
+    /// How many of the pre-spawned flusher threads the pool controller
+    /// currently considers active (as opposed to parked).
+    pub fn active_flusher_threads(&self) -> usize {
+        self.flusher_pool.target_active.load(Ordering::Relaxed)
+    }
+
+    /// Signal the background flusher/controller threads to stop and join
+    /// them with [`DEFAULT_JOIN_TIMEOUT`] per thread. Prefer this over
+    /// relying on `Drop` when the caller needs to know deterministically
+    /// that everything was flushed, and which threads (if any) were not.
+    pub fn shutdown(&mut self) -> ShutdownResult {
+        self.shutdown_with_timeout(DEFAULT_JOIN_TIMEOUT)
+    }
+
+    /// Like [`Self::shutdown`], but with an explicit per-thread join deadline.
+    pub fn shutdown_with_timeout(&mut self, timeout: Duration) -> ShutdownResult {
+        self.exit.store(true, Ordering::Relaxed);
+        self.storage.wait_dirty_or_aged.notify_all();
+
+        let mut report = ShutdownReport::default();
+        if let Some(handles) = self.handles.take() {
+            for handle in handles {
+                let name = handle
+                    .thread()
+                    .name()
+                    .unwrap_or("solana-idx-unknown")
+                    .to_string();
+                match join_with_timeout(handle, timeout) {
+                    JoinOutcome::Joined => report.joined.push(name),
+                    JoinOutcome::Panicked(msg) => {
+                        self.storage.stats.record_flusher_panic(&name, &msg);
+                        report.panicked.push((name, msg));
+                    }
+                    JoinOutcome::TimedOut => {
+                        self.storage.stats.record_flusher_timeout(&name);
+                        report.timed_out.push(name);
+                    }
+                }
+            }
+        }
+
+        // the flusher threads stop without a final pass, so flush whatever
+        // is still dirty and unclaimed here; a bin still claimed by a thread
+        // that panicked or timed out is skipped, and the report says so
+        while let Some(bin) = self.storage.most_urgent_bin() {
+            self.in_mem[bin].flush();
+        }
+
+        if report.is_clean() {
+            Ok(report)
+        } else {
+            Err(ShutdownError(report))
+        }
+    }
+
+    /// Runs on the controller thread for the lifetime of this instance.
+    /// Grows the active flusher count while the dirty-bin backlog stays
+    /// above `BACKLOG_GROW_THRESHOLD` for `BACKLOG_GROW_CYCLES` cycles in a
+    /// row, and shrinks it back down once the backlog has stayed at or below
+    /// `BACKLOG_SHRINK_THRESHOLD` for `BACKLOG_SHRINK_CYCLES` cycles in a row.
+    fn controller(
         storage: Arc<BucketMapHolder<T>>,
         exit: Arc<AtomicBool>,
-        in_mem: Vec<Arc<InMemAccountsIndex<T>>>,
+        flusher_pool: Arc<FlusherPool>,
     ) {
-        let bins = in_mem.len();
-        let flush = storage.disk.is_some();
-        loop {
-            // this will transition to waits and thread throttling
+        let mut cycles_over_threshold = 0u32;
+        let mut cycles_under_threshold = 0u32;
+        while !exit.load(Ordering::Relaxed) {
+            // The `exit` check is folded into the wait itself (rather than
+            // loaded only after `wait_timeout` returns) so that a
+            // `shutdown`/`drop` which stores `exit` and calls `notify_all`
+            // in the gap between this thread's loop iterations can't have
+            // its wakeup lost, leaving this thread asleep for up to
+            // `CONTROLLER_INTERVAL` after shutdown was requested.
             storage
                 .wait_dirty_or_aged
-                .wait_timeout(Duration::from_millis(10000));
+                .wait_timeout_while(CONTROLLER_INTERVAL, || !exit.load(Ordering::Relaxed));
             if exit.load(Ordering::Relaxed) {
                 break;
             }
 
-            storage.stats.active_threads.fetch_add(1, Ordering::Relaxed);
-            for _ in 0..bins {
-                if flush {
-                    let index = storage.next_bucket_to_flush();
-                    in_mem[index].flush();
+            let backlog = storage.stats.dirty_backlog();
+            let latency = storage.stats.last_flush_latency();
+            cycles_over_threshold = if backlog > BACKLOG_GROW_THRESHOLD
+                || latency > FLUSH_LATENCY_GROW_THRESHOLD
+            {
+                cycles_over_threshold + 1
+            } else {
+                0
+            };
+            cycles_under_threshold = if backlog <= BACKLOG_SHRINK_THRESHOLD
+                && latency <= FLUSH_LATENCY_GROW_THRESHOLD
+            {
+                cycles_under_threshold + 1
+            } else {
+                0
+            };
+
+            let active = flusher_pool.target_active.load(Ordering::Relaxed);
+            if cycles_over_threshold >= BACKLOG_GROW_CYCLES && active < flusher_pool.max_threads {
+                flusher_pool
+                    .target_active
+                    .store(active + 1, Ordering::Relaxed);
+                storage.wait_dirty_or_aged.notify_all();
+                cycles_over_threshold = 0;
+                cycles_under_threshold = 0;
+            } else if cycles_under_threshold >= BACKLOG_SHRINK_CYCLES && active > 1 {
+                flusher_pool
+                    .target_active
+                    .store(active - 1, Ordering::Relaxed);
+                cycles_under_threshold = 0;
+            }
+        }
+    }
+
+    // intended to execute in a bg thread
+    pub(crate) fn background(
+        storage: Arc<BucketMapHolder<T>>,
+        exit: Arc<AtomicBool>,
+        in_mem: Vec<Arc<InMemAccountsIndex<T>>>,
+        flusher_pool: Arc<FlusherPool>,
+        id: usize,
+    ) {
+        let mut last_cycle = Instant::now() - MIN_CYCLE_GAP;
+        while !exit.load(Ordering::Relaxed) {
+            // threads beyond the controller's current target stay parked
+            // (no work, no cpu) until it grows the pool far enough to need
+            // them again. Checked *before* `ready_to_flush_at` (which takes
+            // the holder's bin lock to scan for a deadline) so a parked
+            // thread never takes that lock, or contends with the active
+            // thread(s) taking it, while it has nothing to do.
+            if id >= flusher_pool.target_active.load(Ordering::Relaxed) {
+                // `exit` and the pool target are re-checked inside the wait
+                // itself (see `wait_timeout_while`'s doc comment) so this
+                // can't sleep through a shutdown, or through the controller
+                // growing the pool, that lands between loop iterations.
+                storage.wait_dirty_or_aged.wait_timeout_while(MAX_IDLE_WAIT, || {
+                    !exit.load(Ordering::Relaxed)
+                        && id >= flusher_pool.target_active.load(Ordering::Relaxed)
+                });
+                continue;
+            }
+
+            // Sleep only until the holder says some bin is actually due,
+            // rather than blindly polling every 10s. `ready_to_flush_at` is
+            // the time the oldest dirty, unclaimed bin went dirty, so any
+            // dirty bin is due at once (there is no minimum age), and the
+            // wait ends as soon as `mark_bin_dirty` notifies, so a bin that
+            // just went dirty gets serviced promptly instead of waiting out
+            // a full tick, while an index with nothing dirty lets the thread sleep.
+            let wait_for = storage
+                .ready_to_flush_at()
+                .map(|at| at.saturating_duration_since(Instant::now()))
+                .unwrap_or(MAX_IDLE_WAIT)
+                .min(MAX_IDLE_WAIT);
+            storage.wait_dirty_or_aged.wait_timeout_while(wait_for, || {
+                !exit.load(Ordering::Relaxed) && storage.ready_to_flush_at().is_none()
+            });
+            if exit.load(Ordering::Relaxed) {
+                break;
+            }
+
+            // Coalesce wakeups: a burst of bins going dirty at once would
+            // otherwise wake this thread repeatedly in quick succession; if
+            // we just ran a pass, wait out the rest of the minimum gap so
+            // the burst is serviced in one go instead of thrashing.
+            let since_last_cycle = last_cycle.elapsed();
+            if since_last_cycle < MIN_CYCLE_GAP {
+                storage
+                    .wait_dirty_or_aged
+                    .wait_timeout_while(MIN_CYCLE_GAP - since_last_cycle, || {
+                        !exit.load(Ordering::Relaxed)
+                    });
+                if exit.load(Ordering::Relaxed) {
+                    break;
                 }
+            }
+            last_cycle = Instant::now();
+
+            storage.stats.active_threads.fetch_add(1, Ordering::Relaxed);
+            // service the most urgent bin first (oldest dirty entry plus
+            // dirty-entry count), instead of round-robining every bin.
+            // `flush` releases the bin's claim even with no disk to
+            // persist to, so an in-memory-only index's backlog still drains.
+            while let Some(bin) = storage.most_urgent_bin() {
+                let started = Instant::now();
+                in_mem[bin].flush();
+                storage.stats.record_flush_latency(started.elapsed());
                 storage.stats.report_stats(&storage);
             }
             storage.stats.active_threads.fetch_sub(1, Ordering::Relaxed);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn test_storage() -> AccountsIndexStorage<u64> {
+        let storage = Arc::new(BucketMapHolder::new(1, &None));
+        AccountsIndexStorage {
+            exit: Arc::new(AtomicBool::new(false)),
+            handles: Some(Vec::new()),
+            storage,
+            in_mem: Vec::new(),
+            flusher_pool: Arc::new(FlusherPool::new(1, 1)),
+        }
+    }
+
+    #[test]
+    fn shutdown_returns_promptly_for_freshly_spawned_threads() {
+        // exercises the real flusher/controller threads spawned by `new`
+        // (unlike `test_storage`, which hand-builds `handles` and never
+        // starts `background`/`controller`), so a lost wakeup between
+        // `exit.store` + `notify_all` and a thread that hasn't reached its
+        // first `wait_timeout_while` yet would show up as this test taking
+        // up to `MAX_IDLE_WAIT`/`CONTROLLER_INTERVAL` instead of returning
+        // near-instantly.
+        let mut index: AccountsIndexStorage<u64> = AccountsIndexStorage::new(4, &None);
+
+        let started = Instant::now();
+        let report = index
+            .shutdown()
+            .expect("freshly spawned threads should shut down cleanly");
+
+        assert!(report.panicked.is_empty());
+        assert!(report.timed_out.is_empty());
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "shutdown took {:?}, expected a prompt return instead of waiting out an idle timeout",
+            started.elapsed()
+        );
+    }
+
+    #[test]
+    fn shutdown_reports_panicked_thread_instead_of_propagating() {
+        let mut index = test_storage();
+        index.handles.as_mut().unwrap().push(
+            thread::Builder::new()
+                .spawn(|| panic!("boom"))
+                .unwrap(),
+        );
+
+        let result = index.shutdown_with_timeout(Duration::from_millis(500));
+
+        let err = result.expect_err("a panicked flusher should surface as an error");
+        assert_eq!(err.0.panicked.len(), 1);
+        assert!(err.0.panicked[0].1.contains("boom"));
+        assert!(err.0.timed_out.is_empty());
+    }
+
+    #[test]
+    fn shutdown_detaches_thread_that_overruns_its_deadline() {
+        let mut index = test_storage();
+        index.handles.as_mut().unwrap().push(
+            thread::Builder::new()
+                .spawn(|| thread::sleep(Duration::from_secs(2)))
+                .unwrap(),
+        );
+
+        let result = index.shutdown_with_timeout(Duration::from_millis(50));
+
+        let err = result.expect_err("an overrunning flusher should surface as an error");
+        assert_eq!(err.0.timed_out.len(), 1);
+        assert!(err.0.panicked.is_empty());
+        // shutdown itself returned well before the spawned thread's 2s sleep
+    }
+
+    #[test]
+    fn shutdown_is_ok_when_every_thread_exits_cleanly() {
+        let mut index = test_storage();
+        index
+            .handles
+            .as_mut()
+            .unwrap()
+            .push(thread::Builder::new().spawn(|| {}).unwrap());
+
+        let report = index
+            .shutdown_with_timeout(Duration::from_secs(1))
+            .expect("a thread that exits cleanly should not be an error");
+        assert_eq!(report.joined.len(), 1);
+        assert!(report.panicked.is_empty());
+        assert!(report.timed_out.is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+use crate::accounts_index::{AccountsIndexConfig, IndexValue};
+use crate::waitable_condvar::WaitableCondvar;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counters and lightweight diagnostics for a [`BucketMapHolder`]. Read by
+/// the flusher/controller threads every cycle and periodically reported.
+#[derive(Debug, Default)]
+pub struct BucketMapHolderStats {
+    /// number of flusher threads currently mid-flush (not parked, not idle)
+    pub active_threads: AtomicUsize,
+    /// total dirty entries summed across all bins, updated as entries are
+    /// marked dirty and as bins are flushed; read by the pool controller to
+    /// decide whether to grow or shrink the active flusher count
+    dirty_backlog: AtomicUsize,
+    /// flusher/controller threads that panicked during shutdown, paired
+    /// with their panic message, most recent last
+    flusher_panics: Mutex<Vec<(String, String)>>,
+    /// flusher/controller threads that blew through their shutdown join
+    /// deadline and were detached rather than waited on, most recent last
+    flusher_timeouts: Mutex<Vec<String>>,
+    /// wall-clock time the most recently completed single-bin flush took;
+    /// read by the pool controller alongside `dirty_backlog` so a pool
+    /// that's keeping the backlog low only by running slow flushes still
+    /// gets grown
+    last_flush_latency_nanos: AtomicU64,
+}
+
+impl BucketMapHolderStats {
+    /// Current dirty-bin backlog, summed across all bins.
+    pub fn dirty_backlog(&self) -> usize {
+        self.dirty_backlog.load(Ordering::Relaxed)
+    }
+
+    fn add_to_backlog(&self, delta: usize) {
+        self.dirty_backlog.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    fn remove_from_backlog(&self, delta: usize) {
+        self.dirty_backlog.fetch_sub(delta, Ordering::Relaxed);
+    }
+
+    /// How long the most recently completed single-bin flush took.
+    pub fn last_flush_latency(&self) -> Duration {
+        Duration::from_nanos(self.last_flush_latency_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Records how long a single-bin flush just took, overwriting whatever
+    /// was previously recorded.
+    pub fn record_flush_latency(&self, latency: Duration) {
+        self.last_flush_latency_nanos.store(
+            latency.as_nanos().min(u64::MAX as u128) as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Periodic reporting hook; called once per flusher cycle. Metrics
+    /// publishing lives outside this fragment.
+    pub fn report_stats<T: IndexValue>(&self, _storage: &Arc<BucketMapHolder<T>>) {}
+
+    /// Records that `thread_name` panicked while being joined during
+    /// shutdown, so it shows up in stats/metrics even though the panic
+    /// itself is swallowed rather than propagated.
+    pub fn record_flusher_panic(&self, thread_name: &str, message: &str) {
+        log::error!("accounts index flusher thread {thread_name} panicked during shutdown: {message}");
+        self.flusher_panics
+            .lock()
+            .unwrap()
+            .push((thread_name.to_string(), message.to_string()));
+    }
+
+    /// Records that `thread_name` did not finish within its shutdown join
+    /// deadline and was detached rather than waited on indefinitely.
+    pub fn record_flusher_timeout(&self, thread_name: &str) {
+        log::warn!("accounts index flusher thread {thread_name} did not exit before the shutdown deadline; detaching");
+        self.flusher_timeouts
+            .lock()
+            .unwrap()
+            .push(thread_name.to_string());
+    }
+}
+
+/// Per-bin dirty/claim bookkeeping consulted by `most_urgent_bin`.
+#[derive(Default, Clone, Copy)]
+struct BinState {
+    dirty_entries: usize,
+    oldest_dirty_at: Option<Instant>,
+    /// `true` while some flusher thread is already working this bin.
+    claimed: bool,
+    /// bumped every time this bin is reset by `finish_flush`, so a
+    /// `HeapEntry` queued before the reset can be told apart from one
+    /// queued after (the bin may have gone dirty again in between) without
+    /// having to reach into the heap to update or remove it.
+    generation: u64,
+}
+
+/// One bin's entry in `Bins::queue`, ordered oldest-dirty-first (ties
+/// broken by higher dirty-entry count) so `BinaryHeap::pop` hands back the
+/// most urgent bin in `O(log bins)` instead of a linear scan.
+struct HeapEntry {
+    oldest_dirty_at: Instant,
+    dirty_entries: usize,
+    bin: usize,
+    generation: u64,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        // BinaryHeap is a max-heap; flip the timestamp comparison so the
+        // *earliest* dirty entry sorts as the greatest (= popped first).
+        other
+            .oldest_dirty_at
+            .cmp(&self.oldest_dirty_at)
+            .then_with(|| self.dirty_entries.cmp(&other.dirty_entries))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+/// Per-bin state plus the urgency queue over it, behind one mutex so that
+/// picking the most urgent bin and claiming it happen as one atomic step:
+/// two flusher threads racing `most_urgent_bin` can never be handed the
+/// same bin. `queue` may contain stale entries (for a bin that's since been
+/// claimed or re-flushed); those are discarded lazily as they're popped,
+/// checked against `states[bin].generation` rather than removed eagerly.
+#[derive(Default)]
+struct Bins {
+    states: Vec<BinState>,
+    queue: BinaryHeap<HeapEntry>,
+}
+
+/// Owns the dirty/flush bookkeeping shared by every bin's
+/// [`crate::in_mem_accounts_index::InMemAccountsIndex`] and the background
+/// flusher threads in [`crate::accounts_index_storage::AccountsIndexStorage`].
+pub struct BucketMapHolder<T: IndexValue> {
+    /// `Some` once an on-disk bucket map backs this index; `None` keeps
+    /// everything in memory. The flusher threads claim and release dirty
+    /// bins either way, so the backlog accounting drains in both cases.
+    pub disk: Option<()>,
+    pub wait_dirty_or_aged: WaitableCondvar,
+    pub stats: BucketMapHolderStats,
+
+    bin_count: usize,
+    next_bucket_to_flush: AtomicUsize,
+    bins: Mutex<Bins>,
+
+    _phantom: PhantomData<T>,
+}
+
+impl<T: IndexValue> BucketMapHolder<T> {
+    pub fn new(bins: usize, config: &Option<AccountsIndexConfig>) -> Self {
+        let disk = config
+            .as_ref()
+            .and_then(|config| config.drives.as_ref())
+            .map(|_| ());
+        let bin_count = bins.max(1);
+        Self {
+            disk,
+            wait_dirty_or_aged: WaitableCondvar::default(),
+            stats: BucketMapHolderStats::default(),
+            bin_count,
+            next_bucket_to_flush: AtomicUsize::new(0),
+            bins: Mutex::new(Bins {
+                states: vec![BinState::default(); bin_count],
+                queue: BinaryHeap::new(),
+            }),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Round-robins through every bin regardless of how dirty (or not) it
+    /// is. Superseded by `most_urgent_bin` for flush scheduling; kept as a
+    /// simple, allocation-free fallback.
+    pub fn next_bucket_to_flush(&self) -> usize {
+        self.next_bucket_to_flush.fetch_add(1, Ordering::Relaxed) % self.bin_count
+    }
+
+    /// Called whenever an in-mem entry in `bin` is inserted or updated, so
+    /// the flusher's age/backlog accounting and `most_urgent_bin` reflect it.
+    pub fn mark_bin_dirty(&self, bin: usize) {
+        {
+            let mut guard = self.bins.lock().unwrap();
+            let Bins { states, queue } = &mut *guard;
+            let state = &mut states[bin];
+            let was_idle = state.oldest_dirty_at.is_none();
+            state.dirty_entries += 1;
+            let oldest_dirty_at = *state.oldest_dirty_at.get_or_insert_with(Instant::now);
+            // only the bin's first dirty entry changes its queue position
+            // (the ordering key is the *oldest* dirty timestamp), so only
+            // queue a fresh entry on the idle-to-dirty transition
+            if was_idle && !state.claimed {
+                queue.push(HeapEntry {
+                    oldest_dirty_at,
+                    dirty_entries: state.dirty_entries,
+                    bin,
+                    generation: state.generation,
+                });
+            }
+        }
+        self.stats.add_to_backlog(1);
+        self.wait_dirty_or_aged.notify_all();
+    }
+
+    /// Picks the single most urgent dirty, unclaimed bin (oldest dirty
+    /// entry first, then the dirty-entry count the bin had when it was
+    /// queued as a tiebreaker) and atomically marks
+    /// it claimed, all under one lock acquisition. Returns `None` once
+    /// nothing is dirty and unclaimed. Callers must pair a `Some` result
+    /// with a later call to `finish_flush` for the same bin.
+    pub fn most_urgent_bin(&self) -> Option<usize> {
+        let mut guard = self.bins.lock().unwrap();
+        let Bins { states, queue } = &mut *guard;
+        while let Some(entry) = queue.pop() {
+            let state = &mut states[entry.bin];
+            if state.claimed || state.generation != entry.generation {
+                continue; // stale: claimed or reset since this entry was queued
+            }
+            state.claimed = true;
+            return Some(entry.bin);
+        }
+        None
+    }
+
+    /// The time the oldest dirty, unclaimed bin went dirty, or `None` if no
+    /// bin is both dirty and unclaimed. A dirty bin is due at once; there is
+    /// no minimum age. Lets the flusher threads sleep until there's
+    /// actually something to do instead of polling blindly.
+    pub fn ready_to_flush_at(&self) -> Option<Instant> {
+        let mut guard = self.bins.lock().unwrap();
+        let Bins { states, queue } = &mut *guard;
+        loop {
+            let entry = queue.peek()?;
+            let state = &states[entry.bin];
+            if state.claimed || state.generation != entry.generation {
+                queue.pop(); // discard stale entry and keep looking
+                continue;
+            }
+            return Some(entry.oldest_dirty_at);
+        }
+    }
+
+    /// Releases the claim taken by `most_urgent_bin` and clears `bin`'s
+    /// dirty state now that it has been flushed.
+    pub fn finish_flush(&self, bin: usize) {
+        let flushed = {
+            let mut guard = self.bins.lock().unwrap();
+            let state = &mut guard.states[bin];
+            let flushed = state.dirty_entries;
+            state.dirty_entries = 0;
+            state.oldest_dirty_at = None;
+            state.claimed = false;
+            state.generation = state.generation.wrapping_add(1);
+            flushed
+        };
+        self.stats.remove_from_backlog(flushed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn most_urgent_bin_never_double_claims() {
+        const BINS: usize = 50;
+        const THREADS: usize = 8;
+
+        let holder: Arc<BucketMapHolder<u64>> = Arc::new(BucketMapHolder::new(BINS, &None));
+        for bin in 0..BINS {
+            holder.mark_bin_dirty(bin);
+        }
+
+        // line every thread up so they all call `most_urgent_bin`
+        // concurrently instead of serializing through the OS scheduler
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let holder = Arc::clone(&holder);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    let mut claimed = Vec::new();
+                    while let Some(bin) = holder.most_urgent_bin() {
+                        claimed.push(bin);
+                        holder.finish_flush(bin);
+                    }
+                    claimed
+                })
+            })
+            .collect();
+
+        let mut all_claimed = Vec::new();
+        for handle in handles {
+            all_claimed.extend(handle.join().unwrap());
+        }
+
+        // every bin got claimed exactly once across all threads combined
+        assert_eq!(all_claimed.len(), BINS);
+        let unique: HashSet<_> = all_claimed.iter().copied().collect();
+        assert_eq!(unique.len(), BINS, "a bin was handed out more than once");
+        assert_eq!(holder.stats.dirty_backlog(), 0);
+    }
+
+    #[test]
+    fn most_urgent_bin_drains_many_bins_quickly() {
+        // a linear scan under the bins lock would make every pick O(bins);
+        // with the heap this should stay fast even at a scale in the
+        // neighborhood of the real accounts index's bin count.
+        const BINS: usize = 20_000;
+
+        let holder: Arc<BucketMapHolder<u64>> = Arc::new(BucketMapHolder::new(BINS, &None));
+        for bin in 0..BINS {
+            holder.mark_bin_dirty(bin);
+        }
+
+        let started = std::time::Instant::now();
+        let mut drained = 0;
+        while let Some(bin) = holder.most_urgent_bin() {
+            holder.finish_flush(bin);
+            drained += 1;
+        }
+
+        assert_eq!(drained, BINS);
+        assert_eq!(holder.stats.dirty_backlog(), 0);
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "draining {BINS} bins took {:?}, expected O(log bins) picks to stay fast",
+            started.elapsed()
+        );
+    }
+
+    #[test]
+    fn ready_to_flush_at_reflects_oldest_dirty_bin() {
+        let holder: Arc<BucketMapHolder<u64>> = Arc::new(BucketMapHolder::new(4, &None));
+        assert!(holder.ready_to_flush_at().is_none());
+
+        holder.mark_bin_dirty(2);
+        assert!(holder.ready_to_flush_at().is_some());
+
+        let bin = holder.most_urgent_bin().unwrap();
+        assert_eq!(bin, 2);
+        // claimed bins don't count as "ready" again until finished
+        assert!(holder.ready_to_flush_at().is_none());
+
+        holder.finish_flush(bin);
+        assert!(holder.ready_to_flush_at().is_none());
+    }
+}
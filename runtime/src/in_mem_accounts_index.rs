@@ -0,0 +1,37 @@
+use crate::accounts_index::IndexValue;
+use crate::bucket_map_holder::BucketMapHolder;
+use std::sync::Arc;
+
+/// The in-memory view of a single accounts-index bin. Entries written here
+/// get persisted to the on-disk bucket map (when present) by the
+/// background flusher threads owned by
+/// [`crate::accounts_index_storage::AccountsIndexStorage`].
+pub struct InMemAccountsIndex<T: IndexValue> {
+    storage: Arc<BucketMapHolder<T>>,
+    bin: usize,
+}
+
+impl<T: IndexValue> InMemAccountsIndex<T> {
+    pub fn new(storage: &Arc<BucketMapHolder<T>>, bin: usize) -> Self {
+        Self {
+            storage: Arc::clone(storage),
+            bin,
+        }
+    }
+
+    /// Marks this bin dirty, e.g. after an insert or update. Real entry
+    /// storage lives outside this fragment; this only drives the flusher's
+    /// dirty/age bookkeeping in `BucketMapHolder`.
+    pub fn mark_dirty(&self) {
+        self.storage.mark_bin_dirty(self.bin);
+    }
+
+    /// Clears this bin's dirty bookkeeping and releases the claim
+    /// `most_urgent_bin` took on it. Nothing is written to disk here: entry
+    /// storage lives outside this fragment, whether or not the holder has a
+    /// `disk`. Always releasing the claim keeps an in-memory-only index's
+    /// backlog accounting accurate.
+    pub fn flush(&self) {
+        self.storage.finish_flush(self.bin);
+    }
+}
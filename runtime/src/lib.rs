@@ -0,0 +1,5 @@
+pub mod accounts_index;
+pub mod accounts_index_storage;
+pub mod bucket_map_holder;
+pub mod in_mem_accounts_index;
+pub mod waitable_condvar;
@@ -0,0 +1,47 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// A condvar that background threads can wait on, and any other thread can
+/// wake without needing to hold a matching lock around the triggering work.
+#[derive(Debug, Default)]
+pub struct WaitableCondvar {
+    mutex: Mutex<()>,
+    event: Condvar,
+}
+
+impl WaitableCondvar {
+    pub fn notify_all(&self) {
+        let _lock = self.mutex.lock().unwrap();
+        self.event.notify_all();
+    }
+
+    /// Waits up to `timeout`. Returns whether the wait actually timed out
+    /// (as opposed to being woken by a notification).
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let lock = self.mutex.lock().unwrap();
+        let (_lock, result) = self.event.wait_timeout(lock, timeout).unwrap();
+        result.timed_out()
+    }
+
+    /// Waits up to `timeout`, but only for as long as `keep_waiting`
+    /// returns `true`. Unlike checking `keep_waiting` before and after a
+    /// plain `wait_timeout` call, the check happens under the same lock a
+    /// concurrent `notify_all` must also acquire, so a notification sent
+    /// in the gap between "I decided to wait" and "I'm actually asleep"
+    /// can't be lost: either the caller observes the updated state before
+    /// it ever parks, or it's already parked and guaranteed to be woken.
+    /// Returns whether the wait actually timed out (as opposed to exiting
+    /// because `keep_waiting` returned `false`).
+    pub fn wait_timeout_while(
+        &self,
+        timeout: Duration,
+        mut keep_waiting: impl FnMut() -> bool,
+    ) -> bool {
+        let lock = self.mutex.lock().unwrap();
+        let (_lock, result) = self
+            .event
+            .wait_timeout_while(lock, timeout, |_| keep_waiting())
+            .unwrap();
+        result.timed_out()
+    }
+}